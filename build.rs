@@ -0,0 +1,54 @@
+//
+// Copyright:: Copyright (c) 2017 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Captures the handful of build-time facts `fips::build_info::capabilities`
+//! needs to answer "was this binary even built with FIPS support" without
+//! waiting for a runtime failure to find out: the target triple, whether the
+//! `fips` feature was enabled, and the validated module version, taken from
+//! the `FIPS_VALIDATED_MODULE_VERSION` environment variable at build time
+//! (`unknown` when it is unset).
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    let dest_path = Path::new(&out_dir).join("fips_build_info.rs");
+
+    let target = env::var("TARGET").unwrap_or_else(|_| "unknown".to_string());
+    let fips_feature_enabled = env::var("CARGO_FEATURE_FIPS").is_ok();
+    let validated_module_version = env::var("FIPS_VALIDATED_MODULE_VERSION")
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    let generated = format!(
+        "// Generated by build.rs. Do not edit.\n\
+         pub const TARGET: &str = \"{target}\";\n\
+         pub const FIPS_FEATURE_ENABLED: bool = {fips_feature_enabled};\n\
+         pub const CRYPTO_BACKEND: &str = \"rustls+aws-lc-rs\";\n\
+         pub const VALIDATED_MODULE_VERSION: &str = \"{validated_module_version}\";\n",
+        target = target,
+        fips_feature_enabled = fips_feature_enabled,
+        validated_module_version = validated_module_version,
+    );
+
+    fs::write(&dest_path, generated).expect("failed to write fips_build_info.rs");
+
+    println!("cargo:rerun-if-env-changed=FIPS_VALIDATED_MODULE_VERSION");
+    println!("cargo:rerun-if-env-changed=CARGO_FEATURE_FIPS");
+    println!("cargo:rerun-if-changed=build.rs");
+}
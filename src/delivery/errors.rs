@@ -0,0 +1,77 @@
+//
+// Copyright:: Copyright (c) 2017 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// What specifically went wrong. Callers match on this instead of parsing
+/// error strings; `detail` on `DeliveryError` carries the human-readable part.
+#[derive(Debug)]
+pub enum Kind {
+    FipsNotSupportedForChefDKPlatform,
+    /// The in-process FIPS git tunnel (`fips::tunnel`) failed to bind,
+    /// relay, or otherwise operate.
+    FipsTunnelError,
+    /// The aws-lc-rs FIPS crypto provider reported that it is not actually
+    /// operating in FIPS mode (its power-on self-test failed or never ran).
+    FipsSelfTestFailed,
+    /// The FIPS git tunnel did not start accepting verified connections
+    /// before `wait_for_tunnel_ready`'s timeout elapsed.
+    FipsTunnelNotReady,
+    IoError(io::Error),
+}
+
+/// The error type threaded through the whole CLI: a `Kind` plus an optional
+/// human-readable detail message.
+#[derive(Debug)]
+pub struct DeliveryError {
+    pub kind: Kind,
+    pub detail: Option<String>,
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.detail {
+            Some(ref detail) => write!(f, "{}", detail),
+            None => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for DeliveryError {
+    fn description(&self) -> &str {
+        match self.kind {
+            Kind::FipsNotSupportedForChefDKPlatform => {
+                "FIPS mode is not supported on this platform"
+            }
+            Kind::FipsTunnelError => "the FIPS git tunnel failed",
+            Kind::FipsSelfTestFailed => "the FIPS crypto provider failed its self-test",
+            Kind::FipsTunnelNotReady => "the FIPS git tunnel did not become ready in time",
+            Kind::IoError(_) => "an I/O error occurred",
+        }
+    }
+}
+
+impl From<io::Error> for DeliveryError {
+    fn from(err: io::Error) -> DeliveryError {
+        DeliveryError {
+            kind: Kind::IoError(err),
+            detail: None,
+        }
+    }
+}
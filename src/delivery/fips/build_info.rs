@@ -0,0 +1,91 @@
+//
+// Copyright:: Copyright (c) 2017 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A build-time FIPS/crypto capability manifest, generated by `build.rs` so
+//! users and support don't have to reproduce a runtime failure to learn
+//! whether a given binary was even built with FIPS support. Meant to be
+//! surfaced through a `delivery diagnostics` / `--fips-info` command; see
+//! `print_fips_info`'s doc comment for the current state of that wiring.
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/fips_build_info.rs"));
+}
+
+/// What a given `delivery-cli` binary can do around FIPS mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub target: String,
+    pub fips_feature_enabled: bool,
+    pub crypto_backend: String,
+    pub validated_module_version: String,
+}
+
+/// Read back the manifest `build.rs` generated for this binary.
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        target: generated::TARGET.to_string(),
+        fips_feature_enabled: generated::FIPS_FEATURE_ENABLED,
+        crypto_backend: generated::CRYPTO_BACKEND.to_string(),
+        validated_module_version: generated::VALIDATED_MODULE_VERSION.to_string(),
+    }
+}
+
+/// Render the manifest the way `delivery diagnostics`/`--fips-info` prints
+/// it, so support can confirm FIPS availability from a single command
+/// instead of walking someone through reproducing the failure.
+pub fn format_report(caps: &Capabilities) -> String {
+    format!(
+        "target: {target}\n\
+         fips feature enabled: {fips_feature_enabled}\n\
+         crypto backend: {crypto_backend}\n\
+         validated module version: {validated_module_version}\n",
+        target = caps.target,
+        fips_feature_enabled = caps.fips_feature_enabled,
+        crypto_backend = caps.crypto_backend,
+        validated_module_version = caps.validated_module_version,
+    )
+}
+
+/// Prints this binary's capability manifest to stdout. Intended as the
+/// handler for a future `delivery diagnostics` / `--fips-info` command; no
+/// argument parser in this crate calls it yet, so for now it's only reachable
+/// from `fips::print_fips_info` directly (e.g. from a REPL or a test).
+pub fn print_fips_info() {
+    print!("{}", format_report(&capabilities()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_report_includes_every_field() {
+        let caps = Capabilities {
+            target: "x86_64-unknown-linux-gnu".to_string(),
+            fips_feature_enabled: true,
+            crypto_backend: "rustls+aws-lc-rs".to_string(),
+            validated_module_version: "3.0".to_string(),
+        };
+
+        let report = format_report(&caps);
+
+        assert!(report.contains("target: x86_64-unknown-linux-gnu"));
+        assert!(report.contains("fips feature enabled: true"));
+        assert!(report.contains("crypto backend: rustls+aws-lc-rs"));
+        assert!(report.contains("validated module version: 3.0"));
+    }
+}
@@ -0,0 +1,86 @@
+//
+// Copyright:: Copyright (c) 2017 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Installs the FIPS-validated `rustls` crypto backend (aws-lc-rs built with
+//! its `fips` feature) as the process-wide default, so every TLS connection
+//! the `tunnel` module makes is provably using a validated cryptographic
+//! module instead of whatever the platform happened to ship as OpenSSL.
+
+use std::sync::Once;
+
+use rustls::crypto::aws_lc_rs;
+use rustls::crypto::CryptoProvider;
+
+use config::Config;
+use errors::DeliveryError;
+use errors::Kind;
+use types::DeliveryResult;
+
+static INSTALL_ONCE: Once = Once::new();
+
+/// If `config.fips` is set, install the aws-lc-rs FIPS provider as the
+/// process default `CryptoProvider` and confirm it reports that it is
+/// actually running in FIPS mode (aws-lc-rs runs its power-on self-test the
+/// first time the provider is used, so asking `provider.fips()` here forces
+/// that test to have happened before we ever bind the tunnel).
+///
+/// Safe to call more than once; only the first call installs anything.
+pub fn install_fips_provider_if_needed(config: &Config) -> DeliveryResult<()> {
+    install_fips_provider_if_requested(config.fips)
+}
+
+/// The logic behind `install_fips_provider_if_needed`, taking just the
+/// `fips` flag instead of a whole `Config` so it can be exercised directly
+/// in tests without having to build a complete `Config`.
+fn install_fips_provider_if_requested(fips: Option<bool>) -> DeliveryResult<()> {
+    if fips != Some(true) {
+        return Ok(());
+    }
+
+    let provider = aws_lc_rs::default_fips_provider();
+    if !provider.fips() {
+        return Err(DeliveryError {
+            kind: Kind::FipsSelfTestFailed,
+            detail: Some(
+                "the aws-lc-rs crypto provider is not operating in FIPS mode; \
+                 this build of delivery-cli may not have been linked against \
+                 a validated FIPS module".to_string(),
+            ),
+        });
+    }
+
+    INSTALL_ONCE.call_once(|| {
+        // `install_default` fails only if a provider was already installed.
+        // `INSTALL_ONCE` keeps this function from calling it more than once,
+        // and its result is ignored so that a provider installed earlier
+        // elsewhere in the process (e.g. by a test) does not cause a panic.
+        let _ = CryptoProvider::install_default(provider);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn does_nothing_when_fips_is_not_requested() {
+        assert!(install_fips_provider_if_requested(None).is_ok());
+        assert!(install_fips_provider_if_requested(Some(false)).is_ok());
+    }
+}
@@ -18,32 +18,77 @@
 use std;
 use utils;
 use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::io::Write;
+use std::time::Duration;
 use errors::DeliveryError;
-use types::DeliveryResult;
 use errors::Kind;
+use types::DeliveryResult;
 use config::Config;
 
-pub fn setup_and_start_stunnel_if_fips_mode(config: &Config, child_processes: &mut Vec<std::process::Child>) -> DeliveryResult<()> {
+mod build_info;
+mod crypto;
+mod stunnel_config;
+mod tunnel;
+
+pub use self::build_info::{capabilities, format_report, print_fips_info, Capabilities};
+pub use self::stunnel_config::{StunnelConfig, TunnelService};
+pub use self::tunnel::{TunnelHandle, UPSTREAM_PORT};
+
+/// How long `setup_and_start_stunnel_if_fips_mode` will wait for the tunnel
+/// to start accepting verified connections before giving up.
+const TUNNEL_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Set up FIPS mode for this run of the CLI, if `config.fips` asks for it.
+///
+/// By default this installs the FIPS-validated `crypto` provider, starts the
+/// in-process `tunnel` module (which needs no `stunnel` binary on disk), and
+/// blocks until the tunnel is confirmed ready, so callers can start using
+/// `fips_git_port` the moment this function returns. If
+/// `config.fips_stunnel_path` names an external binary (kept only for users
+/// who still want to run a real stunnel), the legacy
+/// config-file-plus-child-process path is used instead, none of the above
+/// applies, and `None` is returned since there is no `TunnelHandle` to hand
+/// back.
+pub fn setup_and_start_stunnel_if_fips_mode(config: &Config) -> DeliveryResult<Option<TunnelHandle>> {
     if let Some(fips) = config.fips {
         if fips {
-            if !Path::new(&utils::stunnel_path()).exists() {
-                return Err(DeliveryError{ kind: Kind::FipsNotSupportedForChefDKPlatform,
-                                          detail: None })
-            }
-
             let server = validate!(config, server);
             let fips_git_port = validate!(config, fips_git_port);
 
-            try!(generate_stunnel_config(&server, &fips_git_port));
             try!(write_stunnel_cert_file(&server,
                                          config.api_port.as_ref().unwrap_or(&"443".to_string())
             ));
-            try!(start_stunnel(child_processes));
+
+            if let Some(ref external_stunnel_path) = config.fips_stunnel_path {
+                // The external binary handles its own TLS entirely, so the
+                // in-process crypto/capability requirements below don't apply.
+                try!(generate_stunnel_config(&server, &fips_git_port));
+                try!(start_external_stunnel(external_stunnel_path));
+                return Ok(None);
+            }
+
+            let caps = build_info::capabilities();
+            if !caps.fips_feature_enabled {
+                return Err(DeliveryError {
+                    kind: Kind::FipsNotSupportedForChefDKPlatform,
+                    detail: Some(format!(
+                        "this delivery-cli binary (target {}, crypto backend {}) was not built \
+                         with FIPS support",
+                        caps.target, caps.crypto_backend
+                    )),
+                });
+            }
+
+            try!(crypto::install_fips_provider_if_needed(config));
+
+            let ca_file = try!(utils::home_dir(&[".chefdk/etc/automate-nginx-cert.pem"]));
+            let handle = try!(tunnel::start_tunnel(&server, &fips_git_port, &ca_file));
+            try!(tunnel::wait_for_tunnel_ready(&fips_git_port, &server, &ca_file, TUNNEL_READY_TIMEOUT));
+            return Ok(Some(handle));
         }
     }
-    Ok(())
+    Ok(None)
 }
 
 pub fn merge_fips_options_and_config(fips: bool, fips_git_port: &str, mut config: Config) -> DeliveryResult<Config> {
@@ -55,29 +100,19 @@ pub fn merge_fips_options_and_config(fips: bool, fips_git_port: &str, mut config
     Ok(new_config)
 }
 
-fn start_stunnel(child_processes: &mut Vec<std::process::Child>) -> DeliveryResult<()> {
-    // On windows, stunnel behaves very differently, so we need to run it as a service,
-    // instead of starting and stopping as a child process via rust as we do in unix.
-    if cfg!(target_os = "windows") {
-        try!(try!(utils::generate_command_from_string(&format!("{stunnel_path} -install -quiet",
-                                                          stunnel_path=utils::stunnel_path()))).output());
-
-        try!(try!(utils::generate_command_from_string(&format!("{stunnel_path} -start -quiet",
-                                                          stunnel_path=utils::stunnel_path()))).output());
-
-        try!(try!(utils::generate_command_from_string(&format!("{stunnel_path} -reload -quiet",
-                                                          stunnel_path=utils::stunnel_path()))).output());
-
-    } else {
-        let unix_stunnel_config_path = try!(stunnel_config_path()).to_str().unwrap().to_string();
-        let mut stunnel_command =
-            try!(utils::generate_command_from_string(&format!("{stunnel_path} {config}",
-                                                              stunnel_path=utils::stunnel_path(),
-                                                              config=unix_stunnel_config_path)
-            ));
-        child_processes.push(try!(stunnel_command.spawn()));
-    };
-
+/// Compatibility fallback for users who explicitly point `config.fips_stunnel_path`
+/// at a real `stunnel` binary instead of using the in-process tunnel. Unlike the
+/// old default path, this no longer drives Windows's `-install`/`-start`/`-reload`
+/// service dance; it just launches the binary against the generated config and
+/// lets it run unsupervised, the way any other externally-managed service would.
+fn start_external_stunnel(stunnel_path: &str) -> DeliveryResult<()> {
+    let unix_stunnel_config_path = try!(stunnel_config_path()).to_str().unwrap().to_string();
+    let mut stunnel_command =
+        try!(utils::generate_command_from_string(&format!("{stunnel_path} {config}",
+                                                          stunnel_path=stunnel_path,
+                                                          config=unix_stunnel_config_path)
+        ));
+    try!(stunnel_command.spawn());
 
     Ok(())
 }
@@ -103,47 +138,29 @@ fn generate_stunnel_config(server: &str, fips_git_port: &str) -> Result<(), Deli
     try!(std::fs::create_dir_all(try!(utils::home_dir(&[".chefdk/etc/"]))));
     try!(std::fs::create_dir_all(try!(utils::home_dir(&[".chefdk/log/"]))));
 
-    let newline_str = if cfg!(target_os = "windows") { "\r\n" } else { "\n" };
+    let output_path = try!(utils::home_dir(&[".chefdk/log/stunnel.log"])).to_str().unwrap().to_string();
+    let ca_file = try!(utils::home_dir(&[".chefdk/etc/automate-nginx-cert.pem"])).to_str().unwrap().to_string();
+
+    let config = StunnelConfig {
+        fips: true,
+        client: true,
+        output_path: output_path,
+        foreground: !cfg!(target_os = "windows"),
+        services: vec![TunnelService {
+            name: "git".to_string(),
+            accept: fips_git_port.to_string(),
+            connect_host: server.to_string(),
+            connect_port: UPSTREAM_PORT,
+            check_host: server.to_string(),
+            verify_chain: true,
+            verify_level: 3,
+            ca_file: ca_file,
+        }],
+    };
 
     let stunnel_path = try!(stunnel_config_path());
     let mut conf_file = try!(File::create(&stunnel_path));
-
-    let fips = "fips = yes".to_string() + newline_str;
-    try!(conf_file.write_all(fips.as_bytes()));
-
-    let client = "client = yes".to_string() + newline_str;
-    try!(conf_file.write_all(client.as_bytes()));
-
-    let output = "output = ".to_string();
-    let output_conf = output + try!(utils::home_dir(&[".chefdk/log/stunnel.log"])).to_str().unwrap() + newline_str;
-    try!(conf_file.write_all(output_conf.as_bytes()));
-
-    if !cfg!(target_os = "windows") {
-        try!(conf_file.write_all(b"foreground = quiet\n"))
-    }
-
-    let git = "[git]".to_string() + newline_str;
-    try!(conf_file.write_all(git.as_bytes()));
-
-    let accept = "accept = ".to_string() + fips_git_port + newline_str;
-    try!(conf_file.write_all(accept.as_bytes()));
-
-    let connect = "connect = ".to_string() + server + ":8989" + newline_str;
-    try!(conf_file.write_all(connect.as_bytes()));
-
-    let check_host = "checkHost = ".to_string() + server + newline_str;
-    try!(conf_file.write_all(check_host.as_bytes()));
-
-    let verify_chain = "verifyChain = yes".to_string() + newline_str;
-    try!(conf_file.write_all(verify_chain.as_bytes()));
-
-    let verify = "verify = 3".to_string() + newline_str;
-    try!(conf_file.write_all(verify.as_bytes()));
-
-    let cert_location_pathbuf = try!(utils::home_dir(&[".chefdk/etc/automate-nginx-cert.pem"]));
-    let cert_location = cert_location_pathbuf.to_str().unwrap();
-    let ca_file = "CAfile = ".to_string() + cert_location + newline_str;
-    try!(conf_file.write_all(ca_file.as_bytes()));
+    try!(conf_file.write_all(config.to_string().as_bytes()));
 
     Ok(())
 }
@@ -155,26 +172,28 @@ mod tests {
 
     #[test]
     fn generate_stunnel_config_test() {
-        let init = r#"fips = yes
-client = yes
-"#;
-        let mut expected = init.to_string();
-        expected += &format!("output = {}",
-                             utils::home_dir(&[".chefdk/log/stunnel.log\n"]).unwrap().to_str().unwrap());
-        expected += r#"foreground = quiet
-[git]
-accept = 36534
-connect = automate.test:8989
-checkHost = automate.test
-verifyChain = yes
-verify = 3
-"#;
-        expected += &format!("CAfile = {}",
-                             utils::home_dir(&[".chefdk/etc/automate-nginx-cert.pem\n"]).unwrap().to_str().unwrap());
         generate_stunnel_config("automate.test", "36534").unwrap();
+
+        let expected = StunnelConfig {
+            fips: true,
+            client: true,
+            output_path: utils::home_dir(&[".chefdk/log/stunnel.log"]).unwrap().to_str().unwrap().to_string(),
+            foreground: !cfg!(target_os = "windows"),
+            services: vec![TunnelService {
+                name: "git".to_string(),
+                accept: "36534".to_string(),
+                connect_host: "automate.test".to_string(),
+                connect_port: UPSTREAM_PORT,
+                check_host: "automate.test".to_string(),
+                verify_chain: true,
+                verify_level: 3,
+                ca_file: utils::home_dir(&[".chefdk/etc/automate-nginx-cert.pem"]).unwrap().to_str().unwrap().to_string(),
+            }],
+        };
+
         let mut f = File::open(utils::home_dir(&[".chefdk/etc/stunnel.conf"]).unwrap()).unwrap();
         let mut actual = String::new();
         f.read_to_string(&mut actual).unwrap();
-        assert_eq!(expected, actual);
+        assert_eq!(expected.to_string(), actual);
     }
 }
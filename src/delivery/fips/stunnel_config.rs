@@ -0,0 +1,126 @@
+//
+// Copyright:: Copyright (c) 2017 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! A typed representation of a stunnel config file, rendered via `Display`
+//! instead of hand-concatenated strings. Only used by the compatibility
+//! fallback in `fips::mod`, which still has to write a real stunnel config
+//! for users who point `fips_stunnel_path` at an external binary.
+
+use std::fmt;
+
+const NEWLINE: &str = if cfg!(target_os = "windows") { "\r\n" } else { "\n" };
+
+/// One `[service]` block: a single accept/connect pairing stunnel should
+/// tunnel on our behalf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelService {
+    pub name: String,
+    pub accept: String,
+    pub connect_host: String,
+    pub connect_port: u16,
+    pub check_host: String,
+    pub verify_chain: bool,
+    pub verify_level: u8,
+    pub ca_file: String,
+}
+
+impl fmt::Display for TunnelService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}]{nl}", self.name, nl = NEWLINE)?;
+        write!(f, "accept = {}{nl}", self.accept, nl = NEWLINE)?;
+        write!(f, "connect = {}:{}{nl}", self.connect_host, self.connect_port, nl = NEWLINE)?;
+        write!(f, "checkHost = {}{nl}", self.check_host, nl = NEWLINE)?;
+        write!(f, "verifyChain = {}{nl}", yes_no(self.verify_chain), nl = NEWLINE)?;
+        write!(f, "verify = {}{nl}", self.verify_level, nl = NEWLINE)?;
+        write!(f, "CAfile = {}{nl}", self.ca_file, nl = NEWLINE)
+    }
+}
+
+/// A full stunnel config file: the global options stunnel expects before the
+/// first `[service]` block, plus the services themselves. Rendering this via
+/// `Display` replaces what used to be dozens of individual `write_all` calls
+/// on hand-built strings, and the upstream connect port is now a field
+/// instead of a literal `8989` baked into the format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StunnelConfig {
+    pub fips: bool,
+    pub client: bool,
+    pub output_path: String,
+    pub foreground: bool,
+    pub services: Vec<TunnelService>,
+}
+
+impl fmt::Display for StunnelConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "fips = {}{nl}", yes_no(self.fips), nl = NEWLINE)?;
+        write!(f, "client = {}{nl}", yes_no(self.client), nl = NEWLINE)?;
+        write!(f, "output = {}{nl}", self.output_path, nl = NEWLINE)?;
+        if self.foreground {
+            write!(f, "foreground = quiet{nl}", nl = NEWLINE)?;
+        }
+        for service in &self.services {
+            write!(f, "{}", service)?;
+        }
+        Ok(())
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_a_single_git_service() {
+        let config = StunnelConfig {
+            fips: true,
+            client: true,
+            output_path: "/home/user/.chefdk/log/stunnel.log".to_string(),
+            foreground: true,
+            services: vec![TunnelService {
+                name: "git".to_string(),
+                accept: "36534".to_string(),
+                connect_host: "automate.test".to_string(),
+                connect_port: 8989,
+                check_host: "automate.test".to_string(),
+                verify_chain: true,
+                verify_level: 3,
+                ca_file: "/home/user/.chefdk/etc/automate-nginx-cert.pem".to_string(),
+            }],
+        };
+
+        let lines = [
+            "fips = yes",
+            "client = yes",
+            "output = /home/user/.chefdk/log/stunnel.log",
+            "foreground = quiet",
+            "[git]",
+            "accept = 36534",
+            "connect = automate.test:8989",
+            "checkHost = automate.test",
+            "verifyChain = yes",
+            "verify = 3",
+            "CAfile = /home/user/.chefdk/etc/automate-nginx-cert.pem",
+        ];
+        let expected = lines.iter().map(|line| format!("{}{}", line, NEWLINE)).collect::<String>();
+
+        assert_eq!(expected, config.to_string());
+    }
+}
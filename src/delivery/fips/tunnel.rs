@@ -0,0 +1,512 @@
+//
+// Copyright:: Copyright (c) 2017 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! An in-process replacement for the external `stunnel` binary.
+//!
+//! Rather than shipping a separate native `stunnel` artifact and shelling out
+//! to it (a Windows service on Windows, a child process everywhere else),
+//! this module binds the FIPS git port itself and relays every accepted
+//! connection to `server:8989` over a `rustls` TLS connection. That removes
+//! an entire class of "where did the platform put stunnel" failures.
+
+use std;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustls;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore};
+use rustls_pemfile;
+
+use errors::DeliveryError;
+use errors::Kind;
+use types::DeliveryResult;
+
+/// The upstream git-over-TLS endpoint that Automate's nginx listens on.
+pub const UPSTREAM_PORT: u16 = 8989;
+
+/// How often a relay thread polls its two non-blocking sockets for more
+/// data. Small enough to feel interactive, large enough not to spin a core.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Bound on a single read/write while driving `verify_upstream_handshake`'s
+/// TLS handshake, so a stalled upstream can't hang a `wait_for_tunnel_ready`
+/// retry indefinitely.
+const HANDSHAKE_IO_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Owns the listening socket and the thread that accepts on it, so the
+/// tunnel can be shut down cleanly. The per-connection relay threads are
+/// detached; their join handles are not kept. This replaces the `Vec<Child>`
+/// the old stunnel-process plumbing threaded through the CLI.
+pub struct TunnelHandle {
+    listener: TcpListener,
+    accept_thread: Option<thread::JoinHandle<()>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl TunnelHandle {
+    /// The address the tunnel is actually listening on. Mainly useful in
+    /// tests, where the caller binds to port `0` and needs to know what was
+    /// assigned.
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Stop accepting new connections and wait for the accept loop to notice.
+    /// In-flight relays are left to finish on their own; they exit the moment
+    /// either side of the connection closes.
+    pub fn shutdown(mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Waking the accept() call requires a dummy connection, since a
+        // plain TcpListener has no way to interrupt a blocking accept.
+        if let Ok(addr) = self.listener.local_addr() {
+            let _ = TcpStream::connect(addr);
+        }
+        if let Some(handle) = self.accept_thread.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Bind `127.0.0.1:<fips_git_port>` and relay every accepted connection,
+/// TLS-encrypted, to `server:8989`. The root of trust is the PEM that
+/// `write_stunnel_cert_file` already writes out for us, and `server` is used
+/// both as the upstream host and the name checked against the presented
+/// certificate (mirroring stunnel's `checkHost`/`verifyChain = yes`/
+/// `verify = 3`).
+pub fn start_tunnel(server: &str, fips_git_port: &str, ca_file: &Path) -> DeliveryResult<TunnelHandle> {
+    let client_config = Arc::new(try!(build_client_config(ca_file)));
+    let server_name = try!(ServerName::try_from(server.to_string()).map_err(|_| DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("'{}' is not a valid TLS server name", server)),
+    }));
+
+    let bind_addr = format!("127.0.0.1:{}", fips_git_port);
+    let listener = try!(TcpListener::bind(&bind_addr).map_err(|e| DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("failed to bind FIPS git tunnel on {}: {}", bind_addr, e)),
+    }));
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let accept_listener = try!(listener.try_clone().map_err(|e| DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("failed to clone tunnel listener: {}", e)),
+    }));
+
+    let upstream_host = server.to_string();
+    let accept_shutdown = shutdown.clone();
+    let accept_thread = thread::spawn(move || {
+        for stream in accept_listener.incoming() {
+            if accept_shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            let client_config = client_config.clone();
+            let server_name = server_name.clone();
+            let upstream_host = upstream_host.clone();
+            thread::spawn(move || {
+                if let Err(e) = relay_connection(stream, &upstream_host, server_name, client_config) {
+                    warn!("fips tunnel: connection relay ended with an error: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(TunnelHandle {
+        listener,
+        accept_thread: Some(accept_thread),
+        shutdown,
+    })
+}
+
+/// Block until the tunnel bound at `127.0.0.1:<fips_git_port>` is accepting
+/// connections, and that an independent TLS handshake to `server:8989`
+/// verifies against the CA in `ca_file`. Without this, the very first `git
+/// push`/`clone` issued right after `start_tunnel` returns can race the
+/// listener and fail spuriously.
+///
+/// Retries with bounded exponential backoff until `timeout` elapses, at
+/// which point a descriptive error is returned instead of leaving the
+/// caller to guess why git traffic is failing.
+pub fn wait_for_tunnel_ready(fips_git_port: &str, server: &str, ca_file: &Path, timeout: Duration) -> DeliveryResult<()> {
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_millis(20);
+
+    loop {
+        let listener_up = TcpStream::connect(("127.0.0.1", try!(parse_port(fips_git_port)))).is_ok();
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if listener_up && remaining > Duration::from_millis(0)
+            && verify_upstream_handshake(server, ca_file, remaining).is_ok()
+        {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            return Err(DeliveryError {
+                kind: Kind::FipsTunnelNotReady,
+                detail: Some(format!(
+                    "FIPS git tunnel on port {} was not accepting verified TLS connections \
+                     to {} after {:?}",
+                    fips_git_port, server, timeout
+                )),
+            });
+        }
+
+        thread::sleep(backoff);
+        backoff = std::cmp::min(backoff * 2, Duration::from_millis(500));
+    }
+}
+
+fn parse_port(fips_git_port: &str) -> DeliveryResult<u16> {
+    fips_git_port.parse().map_err(|_| DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("'{}' is not a valid port number", fips_git_port)),
+    })
+}
+
+/// Open a one-off TLS connection straight to `server:8989`, bypassing the
+/// local tunnel, and drive the handshake to completion. This is the same
+/// check the tunnel itself relies on for every relayed connection, so a
+/// success here is a strong signal that relayed connections will succeed too.
+///
+/// `budget` bounds both the TCP connect and the read/write timeouts used
+/// while driving the handshake, so that a firewalled or hung upstream can
+/// never outlast `wait_for_tunnel_ready`'s own deadline: an unbounded
+/// `TcpStream::connect` alone would defeat that deadline, since the OS's
+/// default connect timeout is typically far longer than it.
+fn verify_upstream_handshake(server: &str, ca_file: &Path, budget: Duration) -> DeliveryResult<()> {
+    let client_config = Arc::new(try!(build_client_config(ca_file)));
+    let server_name = try!(ServerName::try_from(server.to_string()).map_err(|_| DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("'{}' is not a valid TLS server name", server)),
+    }));
+
+    let io_timeout = std::cmp::min(budget, HANDSHAKE_IO_TIMEOUT);
+
+    let mut upstream_addrs = try!((server, UPSTREAM_PORT).to_socket_addrs().map_err(|e| DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("failed to resolve {}:{}: {}", server, UPSTREAM_PORT, e)),
+    }));
+    let upstream_addr = try!(upstream_addrs.next().ok_or_else(|| DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("'{}' did not resolve to any address", server)),
+    }));
+
+    let mut conn = try!(ClientConnection::new(client_config, server_name)
+        .map_err(|e| tls_error(e)));
+    let mut sock = try!(TcpStream::connect_timeout(&upstream_addr, io_timeout).map_err(|e| DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("failed to reach {}:{}: {}", server, UPSTREAM_PORT, e)),
+    }));
+    // A stalled upstream (firewalled server, hung nginx) must not be allowed
+    // to block the handshake loop below forever; bound each read/write so a
+    // single retry can never outlast `wait_for_tunnel_ready`'s own timeout.
+    try!(sock.set_read_timeout(Some(io_timeout)).map_err(|e| io_error(e)));
+    try!(sock.set_write_timeout(Some(io_timeout)).map_err(|e| io_error(e)));
+
+    while conn.is_handshaking() {
+        if conn.wants_write() {
+            try!(conn.write_tls(&mut sock).map_err(|e| io_error(e)));
+        }
+        if conn.wants_read() {
+            try!(conn.read_tls(&mut sock).map_err(|e| io_error(e)));
+            try!(conn.process_new_packets().map_err(|e| tls_error(e)));
+        }
+    }
+
+    Ok(())
+}
+
+fn tls_error(e: rustls::Error) -> DeliveryError {
+    DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("TLS handshake to upstream git server failed: {}", e)),
+    }
+}
+
+fn io_error(e: io::Error) -> DeliveryError {
+    DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("I/O error talking to upstream git server: {}", e)),
+    }
+}
+
+/// Load the CA bundle `write_stunnel_cert_file` wrote and build a rustls
+/// client configuration that trusts only it, with no client certificate.
+fn build_client_config(ca_file: &Path) -> DeliveryResult<ClientConfig> {
+    let cert_file = try!(File::open(ca_file).map_err(|e| DeliveryError {
+        kind: Kind::FipsTunnelError,
+        detail: Some(format!("failed to open {}: {}", ca_file.display(), e)),
+    }));
+    let mut reader = BufReader::new(cert_file);
+    let certs: Vec<CertificateDer<'static>> = try!(rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, io::Error>>()
+        .map_err(|e| DeliveryError {
+            kind: Kind::FipsTunnelError,
+            detail: Some(format!("failed to parse {}: {}", ca_file.display(), e)),
+        }));
+
+    let mut root_store = RootCertStore::empty();
+    for cert in certs {
+        try!(root_store.add(cert).map_err(|e| DeliveryError {
+            kind: Kind::FipsTunnelError,
+            detail: Some(format!("invalid certificate in {}: {}", ca_file.display(), e)),
+        }));
+    }
+
+    // `ClientConfig::builder()` picks up whatever `CryptoProvider` the process
+    // installed as its default. `crypto::install_fips_provider_if_needed`
+    // guarantees that, in FIPS mode, this is the aws-lc-rs FIPS provider.
+    Ok(ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth())
+}
+
+/// Bidirectionally copy bytes between a plaintext `git` client socket and a
+/// fresh TLS connection to `server:8989`, until either side closes.
+fn relay_connection(
+    plain: TcpStream,
+    server: &str,
+    server_name: ServerName<'static>,
+    client_config: Arc<ClientConfig>,
+) -> io::Result<()> {
+    let upstream = TcpStream::connect((server, UPSTREAM_PORT))?;
+    relay_over(plain, upstream, server_name, client_config)
+}
+
+/// The actual duplex relay, split out from `relay_connection` so tests can
+/// hand it an already-connected upstream socket (e.g. a loopback TLS test
+/// server) instead of having to reach `server:8989` for real.
+fn relay_over(
+    plain: TcpStream,
+    mut upstream: TcpStream,
+    server_name: ServerName<'static>,
+    client_config: Arc<ClientConfig>,
+) -> io::Result<()> {
+    plain.set_nonblocking(true)?;
+    upstream.set_nonblocking(true)?;
+
+    let mut conn = ClientConnection::new(client_config, server_name)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    let mut plain = plain;
+    let mut buf = [0u8; 8192];
+
+    loop {
+        if conn.wants_write() {
+            match conn.write_tls(&mut upstream) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if conn.wants_read() {
+            match conn.read_tls(&mut upstream) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {
+                    conn.process_new_packets()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        match plain.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => conn.writer().write_all(&buf[..n])?,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut plaintext = Vec::new();
+        match conn.reader().read_to_end(&mut plaintext) {
+            Ok(_) | Err(_) => {}
+        }
+        if !plaintext.is_empty() {
+            plain.write_all(&plaintext)?;
+        }
+
+        if conn.is_closed() {
+            return Ok(());
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::generate_simple_self_signed;
+    use rustls::{ServerConfig, ServerConnection};
+    use std::sync::mpsc;
+
+    /// A minimal loopback TLS "upstream" standing in for Automate's nginx,
+    /// backed by a freshly generated self-signed cert. Returns the address
+    /// to connect to and the matching client `RootCertStore`.
+    fn start_test_upstream() -> (std::net::SocketAddr, RootCertStore) {
+        let cert = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = CertificateDer::from(cert.serialize_der().unwrap());
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.serialize_private_key_der()));
+
+        let mut root_store = RootCertStore::empty();
+        root_store.add(cert_der.clone()).unwrap();
+
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let server_config = Arc::new(
+            ServerConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert_der], key_der)
+                .unwrap(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            if let Ok((sock, _)) = listener.accept() {
+                let mut sock = sock;
+                let mut conn = ServerConnection::new(server_config).unwrap();
+                let mut tls = rustls::Stream::new(&mut conn, &mut sock);
+                let mut buf = [0u8; 8192];
+                loop {
+                    match tls.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => {
+                            if tls.write_all(&buf[..n]).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        (addr, root_store)
+    }
+
+    #[test]
+    fn relay_over_forwards_bytes_in_both_directions() {
+        let (upstream_addr, root_store) = start_test_upstream();
+
+        let client_config = Arc::new(
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(root_store)
+                .with_no_client_auth(),
+        );
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let upstream = TcpStream::connect(upstream_addr).unwrap();
+
+        let (client_sock, plain) = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let (sock, _) = listener.accept().unwrap();
+                tx.send(sock).unwrap();
+            });
+            let plain = TcpStream::connect(addr).unwrap();
+            (rx.recv().unwrap(), plain)
+        };
+
+        let relay_thread =
+            thread::spawn(move || relay_over(client_sock, upstream, server_name, client_config));
+
+        let mut plain = plain;
+        plain.write_all(b"ping").unwrap();
+
+        let mut buf = [0u8; 4];
+        plain.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+
+        drop(plain);
+        relay_thread.join().unwrap().ok();
+    }
+
+    #[test]
+    fn wait_for_tunnel_ready_times_out_when_nothing_is_listening() {
+        let unused_port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+
+        let result = wait_for_tunnel_ready(
+            &unused_port.to_string(),
+            "automate.test",
+            Path::new("/nonexistent/ca.pem"),
+            Duration::from_millis(200),
+        );
+
+        match result {
+            Err(DeliveryError { kind: Kind::FipsTunnelNotReady, .. }) => {}
+            other => panic!("expected FipsTunnelNotReady, got {:?}", other),
+        }
+    }
+
+    /// A listener that accepts the connection and then never reads or
+    /// writes anything, unlike the instant `ECONNREFUSED` above. This is the
+    /// scenario `verify_upstream_handshake`'s `connect_timeout`/read-write
+    /// timeouts actually exist to bound: a firewalled or hung upstream that
+    /// accepts TCP but never speaks TLS.
+    #[test]
+    fn verify_upstream_handshake_times_out_against_a_stalled_upstream() {
+        let cert = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let ca_path = std::env::temp_dir().join("fips_tunnel_test_stalled_upstream_ca.pem");
+        File::create(&ca_path)
+            .unwrap()
+            .write_all(cert.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+
+        let listener = TcpListener::bind(("127.0.0.1", UPSTREAM_PORT)).unwrap();
+        thread::spawn(move || {
+            if let Ok((sock, _)) = listener.accept() {
+                // Hold the connection open without ever reading or writing,
+                // long enough to outlast the budget passed below.
+                thread::sleep(Duration::from_secs(5));
+                drop(sock);
+            }
+        });
+
+        let started = Instant::now();
+        let result = verify_upstream_handshake("127.0.0.1", &ca_path, Duration::from_millis(200));
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "verify_upstream_handshake should have given up within its budget, took {:?}",
+            started.elapsed()
+        );
+        assert!(result.is_err());
+
+        let _ = std::fs::remove_file(&ca_path);
+    }
+}